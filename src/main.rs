@@ -1,29 +1,159 @@
-// This code silently fails on Firefox.  My guess is it's related to
+// Storing the raw `File` object via `store.add` silently fails on
+// Firefox.  My guess is it's related to
 // https://github.com/devashishdxt/rexie/issues/23 which suggests that
-// serializing the files cause problems.  There's a chance that I can
-// serialize the object_url instead (it's just a string) and have
-// something that works with Firefox as well, without jumping through
-// hoops to do additional serialization.
-//
-// FWIW, this code works fine with Brave, Edge and Safari. :-(
+// serializing the files cause problems.  Instead of fighting that, we
+// store a plain JS record of the file's bytes (plus its metadata) and
+// rebuild a `Blob` from it on read, which works the same way across
+// browsers.
 
 use {
     gloo_events::EventListener,
-    gloo_utils::document,
+    gloo_utils::{document, window},
+    js_sys::{Object, Reflect, Uint8Array},
     log::{error, info},
-    indexed_db::{Error, Factory, Index, ObjectStore, Database, Transaction},
-    wasm_bindgen::JsCast,
-    web_sys::{Blob, File, HtmlInputElement, Url},
+    indexed_db::{Error, Factory, Index, ObjectStore, Database, Transaction, TransactionMode, VersionChangeEvent},
+    std::{cell::RefCell, future::Future, pin::Pin, rc::Rc, task::Poll},
+    wasm_bindgen::{closure::Closure, JsCast, JsValue},
+    wasm_bindgen_futures::JsFuture,
+    web_sys::{
+        Blob, BlobPropertyBag, File, HtmlImageElement, HtmlInputElement, ImageEncodeOptions,
+        OffscreenCanvas, OffscreenCanvasRenderingContext2d, Request, RequestInit, Response, Url,
+    },
     yew::{html::Scope, platform::spawn_local, prelude::*},
 };
 
 const DB_NAME: &str = "mb";
 const KEY: &str = "id";
 const INDEX: &str = "file";
+const HASH_INDEX: &str = "hash";
 const BUTTONS: &str = "buttons";
+const CHANGES: &str = "changes";
+const CHANGE_HASH_INDEX: &str = "hash";
+
+// The current schema version. Bump this, and add a matching entry to
+// `migrations`, any time the shape of a stored record changes.
+const DB_VERSION: u32 = 3;
 
 type OurError = ();
 
+// A record a migration step wants dimensions backfilled onto, as the
+// plain `(key, value)` pair a cursor read inside the versionchange
+// transaction - collected there because decoding the image to get
+// those dimensions can't happen in that transaction; see
+// `backfill_dimensions`.
+type PendingBackfill = RefCell<Vec<(JsValue, JsValue)>>;
+
+type Migration =
+    for<'a> fn(&'a VersionChangeEvent<OurError>, &'a PendingBackfill) -> Pin<Box<dyn Future<Output = Result<(), Error<OurError>>> + 'a>>;
+
+// Migration steps, in order, keyed by the version each one brings the
+// database *to*. They all run inside the single atomic versionchange
+// transaction the browser hands us for the whole jump from whatever
+// version is already on disk up to `DB_VERSION`: on open, every step
+// whose target exceeds `evt.old_version()` runs, in order, and a
+// failure in any of them aborts the whole upgrade so the database is
+// never left half-migrated. A step may only use that transaction for
+// plain IndexedDB requests (cursor reads, `add`/`put`/`delete`) -
+// anything that awaits a macrotask (like decoding an image) lets the
+// transaction auto-commit out from under it, so such work is deferred
+// past the upgrade via `pending`; see `migrate_to_v2`.
+fn migrations() -> &'static [(u32, Migration)] {
+    &[
+        (1, |evt, _pending| Box::pin(migrate_to_v1(evt))),
+        (2, |evt, pending| Box::pin(migrate_to_v2(evt, pending))),
+        (3, |evt, _pending| Box::pin(migrate_to_v3(evt))),
+    ]
+}
+
+// v1: create the buttons store with its compound file-identity index
+// and its content-hash index.
+async fn migrate_to_v1(evt: &VersionChangeEvent<OurError>) -> Result<(), Error<OurError>> {
+    let db = evt.database();
+    let store = db.build_object_store(BUTTONS).auto_increment().create()?;
+    store.build_compound_index(INDEX, &["name", "lastModified", "size", "type"]).unique().create().inspect_err(|e| error!("could not build unique index"))?;
+    store.build_index(HASH_INDEX, "hash").unique().create().inspect_err(|e| error!("could not build hash index"))?;
+    Ok(())
+}
+
+// v2: queue every already-stored record that's missing `width`/
+// `height` so their dimensions get backfilled, so buttons persisted
+// before we started recording intrinsic dimensions don't cause
+// layout shift either. This only drains the cursor into `pending` -
+// it can't decode the images here. Awaiting an `<img>`'s `load` event
+// yields on a macrotask, and IndexedDB auto-commits a transaction the
+// moment it goes a tick without a pending request; on any database
+// with real rows, that would silently kill this versionchange
+// transaction mid-iteration and leave every later `entry.update()` (or
+// even the next `cursor.next()`) throwing `TransactionInactiveError`,
+// permanently wedging the upgrade. The decode-and-write-back half
+// happens in `backfill_dimensions`, after this transaction (and the
+// whole upgrade) has committed.
+async fn migrate_to_v2(evt: &VersionChangeEvent<OurError>, pending: &PendingBackfill) -> Result<(), Error<OurError>> {
+    let store = evt.transaction().store(BUTTONS)?;
+    let mut cursor = store.cursor().open().await?;
+    while let Some(entry) = cursor.next().await? {
+        let record = entry.value();
+        if record_dimensions(&record).is_none() {
+            pending.borrow_mut().push((entry.key(), record));
+        }
+    }
+    Ok(())
+}
+
+// Decodes dimensions for the records `migrate_to_v2` flagged and
+// writes them back through a fresh, ordinary transaction - run once
+// the versionchange transaction that collected them has already
+// committed, so decoding (which awaits a macrotask) can't pull the
+// rug out from under it.
+async fn backfill_dimensions(db: &Database<OurError>, pending: Vec<(JsValue, JsValue)>) {
+    if pending.is_empty() {
+        return;
+    }
+
+    let mut backfilled = Vec::with_capacity(pending.len());
+    for (key, record) in pending {
+        match decode_record_dimensions(&record).await {
+            Ok((width, height)) => {
+                let set = Reflect::set(&record, &JsValue::from_str("width"), &width.into())
+                    .and_then(|_| Reflect::set(&record, &JsValue::from_str("height"), &height.into()));
+                match set {
+                    Ok(_) => backfilled.push((key, record)),
+                    Err(e) => error!("could not set width/height on backfilled record: {e:?}"),
+                }
+            }
+            Err(e) => error!("Could not decode dimensions for {record:?}, leaving it as-is: {e:?}"),
+        }
+    }
+
+    let transaction = match db.transaction(&[BUTTONS], TransactionMode::ReadWrite) {
+        Ok(t) => t,
+        Err(e) => return error!("Could not open a transaction to backfill dimensions: {e:?}"),
+    };
+    let store = match transaction.store(BUTTONS) {
+        Ok(s) => s,
+        Err(e) => return error!("Could not get the buttons store to backfill dimensions: {e:?}"),
+    };
+    for (key, record) in backfilled {
+        if let Err(e) = store.put(&record, Some(&key)).await {
+            error!("Could not write back backfilled dimensions: {e:?}");
+        }
+    }
+    if let Err(e) = transaction.done().await {
+        error!("Could not complete the dimension backfill transaction: {e:?}");
+    }
+}
+
+// v3: add the `changes` store that backs sync. Every local add/delete
+// gets a row here, keyed by an auto-incrementing counter, so `sync`
+// can ship "everything since the last counter we acked" to a server
+// and reconcile it against whatever the server has by content hash.
+async fn migrate_to_v3(evt: &VersionChangeEvent<OurError>) -> Result<(), Error<OurError>> {
+    let db = evt.database();
+    let store = db.build_object_store(CHANGES).auto_increment().create()?;
+    store.build_index(CHANGE_HASH_INDEX, "hash").create().inspect_err(|e| error!("could not build changes hash index"))?;
+    Ok(())
+}
+
 async fn build_database(link: Scope<App>) {
     let factory = match Factory::<OurError>::get() {
         Ok(f) => f,
@@ -32,34 +162,139 @@ async fn build_database(link: Scope<App>) {
             return;
         }
     };
-   
-    match factory.open(DB_NAME, 1, |evt| async move {
-        let db = evt.database();
-        let store = db.build_object_store(BUTTONS)
-            .auto_increment()
-            .create()?;
-        store.build_compound_index(INDEX, &["name", "lastModified", "size", "type"]).unique().create().inspect_err(|e| error!("could not build unique index"))?;
-        Ok(())
+
+    // Anything a migration step queued for backfill (see
+    // `PendingBackfill`) while the versionchange transaction was live.
+    let pending: Rc<PendingBackfill> = Rc::new(RefCell::new(Vec::new()));
+    let pending_for_upgrade = Rc::clone(&pending);
+
+    match factory.open(DB_NAME, DB_VERSION, move |evt| {
+        let pending = Rc::clone(&pending_for_upgrade);
+        async move {
+            let old_version = evt.old_version();
+            for (target, step) in migrations() {
+                if *target > old_version {
+                    step(&evt, &pending).await?;
+                }
+            }
+            Ok(())
+        }
     }).await {
         Err(_) => error!("Could not build buttons database"),
-        Ok(db) => link.send_message(Msg::DbBuilt(db)),
+        Ok(db) => {
+            backfill_dimensions(&db, pending.borrow_mut().drain(..).collect()).await;
+            link.send_message(Msg::DbBuilt(db));
+        }
     }
 }
 
+// Builds a `Blob` out of raw bytes and a MIME type, the way we store
+// both in a record.
+fn blob_from_bytes(bytes: &Uint8Array, content_type: &str) -> Result<Blob, JsValue> {
+    let parts = js_sys::Array::new();
+    parts.push(bytes);
+    Blob::new_with_u8_array_sequence_and_options(
+        &parts,
+        BlobPropertyBag::new().type_(content_type),
+    )
+}
+
+// Rebuilds a `Blob` from the `{ bytes, type }` record we stored, so
+// that it can be turned back into an object_url the same way a `File`
+// would have been.
+fn record_to_blob(record: &JsValue) -> Result<Blob, JsValue> {
+    let bytes = Reflect::get(record, &JsValue::from_str("bytes"))?;
+    let bytes: Uint8Array = bytes.dyn_into()?;
+    let content_type = Reflect::get(record, &JsValue::from_str("type"))?
+        .as_string()
+        .unwrap_or_default();
+    blob_from_bytes(&bytes, &content_type)
+}
+
+// Loads `url` (expected to point at an image) into an
+// `HtmlImageElement` and waits for it to decode.
+async fn load_image(url: &str) -> Result<HtmlImageElement, JsValue> {
+    let image = HtmlImageElement::new()?;
+    image.set_src(url);
+
+    let promise = js_sys::Promise::new(&mut |resolve, reject| {
+        // Only one of onload/onerror ever fires. Keep both closures
+        // behind one shared slot so the one that fires can clear the
+        // handlers and drop them both, instead of `once_into_js`
+        // leaking whichever one doesn't.
+        type Handlers = (Closure<dyn FnMut()>, Closure<dyn FnMut(JsValue)>);
+        let handlers: Rc<RefCell<Option<Handlers>>> = Rc::new(RefCell::new(None));
+
+        let onload_image = image.clone();
+        let onload_handlers = handlers.clone();
+        let onload = Closure::once(move || {
+            onload_image.set_onload(None);
+            onload_image.set_onerror(None);
+            onload_handlers.borrow_mut().take();
+            let _ = resolve.call0(&JsValue::NULL);
+        });
+
+        let onerror_image = image.clone();
+        let onerror_handlers = handlers.clone();
+        let onerror = Closure::once(move |e: JsValue| {
+            onerror_image.set_onload(None);
+            onerror_image.set_onerror(None);
+            onerror_handlers.borrow_mut().take();
+            let _ = reject.call1(&JsValue::NULL, &e);
+        });
+
+        image.set_onload(Some(onload.as_ref().unchecked_ref()));
+        image.set_onerror(Some(onerror.as_ref().unchecked_ref()));
+        *handlers.borrow_mut() = Some((onload, onerror));
+    });
+    JsFuture::from(promise).await?;
+
+    Ok(image)
+}
+
+// Decodes `url` (expected to point at an image) just far enough to
+// read off its intrinsic pixel dimensions.
+async fn decode_dimensions(url: &str) -> Result<(u32, u32), JsValue> {
+    let image = load_image(url).await?;
+    Ok((image.natural_width(), image.natural_height()))
+}
+
+// Decodes the dimensions of a stored `{ bytes, type }` record by
+// rebuilding its Blob and loading it into an `HtmlImageElement`.
+async fn decode_record_dimensions(record: &JsValue) -> Result<(u32, u32), JsValue> {
+    let blob = record_to_blob(record)?;
+    let url = Url::create_object_url_with_blob(&blob)?;
+    let dimensions = decode_dimensions(&url).await;
+    let _ = Url::revoke_object_url(&url);
+    dimensions
+}
+
+// Reads the `width`/`height` a record was backfilled or stored with,
+// if any.
+fn record_dimensions(record: &JsValue) -> Option<(u32, u32)> {
+    let width = Reflect::get(record, &JsValue::from_str("width")).ok()?.as_f64()?;
+    let height = Reflect::get(record, &JsValue::from_str("height")).ok()?.as_f64()?;
+    Some((width as u32, height as u32))
+}
+
 async fn async_read_buttons(store: ObjectStore<OurError>, link: Scope<App>) {
     match store.get_all(None).await {
         Err(e) => error!("reading buttons failed: {e:?}"),
-        Ok(files) => {
-            let buttons = files
+        Ok(records) => {
+            let buttons = records
                 .into_iter()
-                .filter_map(|file| match file.dyn_ref::<Blob>() {
-                    None => {
-                        error!("Could not turn {file:?} into Blob");
+                .filter_map(|record| match record_to_blob(&record) {
+                    Err(e) => {
+                        error!("Could not turn {record:?} into Blob: {e:?}");
                         None
                     }
-                    Some(blob) => Url::create_object_url_with_blob(blob)
+                    Ok(blob) => Url::create_object_url_with_blob(&blob)
                         .inspect_err(|e| error!("Could not turn {blob:?} into object_url: {e:?}"))
-                        .ok(),
+                        .ok()
+                        .map(|url| CustomFace {
+                            url,
+                            dimensions: record_dimensions(&record),
+                        }),
                 })
                 .collect();
             link.send_message(Msg::ButtonsRead(buttons));
@@ -68,7 +303,6 @@ async fn async_read_buttons(store: ObjectStore<OurError>, link: Scope<App>) {
 }
 
 fn read_buttons(db: &Database<OurError>, link: Scope<App>) {
-    /*
     let transaction = match db.transaction(&STORE_NAMES, TransactionMode::ReadOnly) {
         Ok(t) => t,
         Err(e) => {
@@ -84,22 +318,208 @@ fn read_buttons(db: &Database<OurError>, link: Scope<App>) {
         }
     };
     spawn_local(async_read_buttons(store, link));
-    */
-}
-
-// If we wanted to, we could split this into a non-async store_button
-// and an async async_store_button, like we do with read_buttons and
-// async_read_buttons above.  The upside to doing the split is that
-// nothing has to be added to the executor in the case where there's
-// an error before anything async is called. That's not much of an
-// upside though if the error is unlikely to occur and time isn't
-// critical.
-//
-// So, the reason read_buttons is split and store_button isn't is just
-// due to me fooling around, since I'm not particularly proficient in
-// async rust.
-async fn store_button(t: Transaction<OurError>, file: File) {
-    /*
+}
+
+// Hex-encodes the SHA-256 digest of `bytes`, so that image content can
+// be addressed and deduplicated independently of filename/mtime.
+async fn sha256_hex(bytes: &Uint8Array) -> Result<String, JsValue> {
+    let subtle = window().crypto()?.subtle();
+    let digest = JsFuture::from(subtle.digest_with_u8_array("SHA-256", &mut bytes.to_vec())?).await?;
+    let digest = Uint8Array::new(&digest);
+    Ok(digest.to_vec().iter().map(|b| format!("{b:02x}")).collect())
+}
+
+// Buttons larger than this on their long edge get downscaled before
+// storage, so a handful of phone-camera photos don't bloat IndexedDB.
+const MAX_EDGE: u32 = 512;
+
+// If `width`x`height` exceeds `MAX_EDGE` on either edge, returns the
+// dimensions to scale down to (preserving aspect ratio). Returns
+// `None` if the image is already small enough.
+fn scaled_dimensions(width: u32, height: u32) -> Option<(u32, u32)> {
+    if width <= MAX_EDGE && height <= MAX_EDGE {
+        None
+    } else {
+        let scale = f64::from(MAX_EDGE) / f64::from(width.max(height));
+        Some((
+            ((f64::from(width) * scale).round() as u32).max(1),
+            ((f64::from(height) * scale).round() as u32).max(1),
+        ))
+    }
+}
+
+// At most this many images get drawn onto a canvas and re-encoded at
+// once, so picking several files in quick succession doesn't leave
+// unbounded spawn_local tasks all fighting over canvas time at the
+// same moment. Decoding each image to read its size happens before a
+// slot is taken and is not bounded by this.
+const MAX_CONCURRENT_RESIZES: usize = 2;
+
+#[derive(Default)]
+struct ResizeQueueState {
+    in_flight: usize,
+    waiters: Vec<std::task::Waker>,
+}
+
+thread_local! {
+    static RESIZE_QUEUE: Rc<RefCell<ResizeQueueState>> = Rc::new(RefCell::new(ResizeQueueState::default()));
+}
+
+// A held slot in the resize queue; releases it (and wakes the next
+// waiter, if any) on drop.
+struct ResizeSlot(Rc<RefCell<ResizeQueueState>>);
+
+impl Drop for ResizeSlot {
+    fn drop(&mut self) {
+        let mut state = self.0.borrow_mut();
+        state.in_flight -= 1;
+        if let Some(waker) = state.waiters.pop() {
+            drop(state);
+            waker.wake();
+        }
+    }
+}
+
+async fn acquire_resize_slot() -> ResizeSlot {
+    let queue = RESIZE_QUEUE.with(Rc::clone);
+    std::future::poll_fn(|cx| {
+        let mut state = queue.borrow_mut();
+        if state.in_flight < MAX_CONCURRENT_RESIZES {
+            state.in_flight += 1;
+            Poll::Ready(())
+        } else {
+            state.waiters.push(cx.waker().clone());
+            Poll::Pending
+        }
+    })
+    .await;
+    ResizeSlot(queue)
+}
+
+// Draws `image` onto a scratch canvas at `width`x`height` and
+// re-encodes it, preferring WebP and falling back to PNG if the
+// browser can't produce WebP.
+async fn downscale(image: &HtmlImageElement, width: u32, height: u32) -> Result<Blob, JsValue> {
+    let canvas = OffscreenCanvas::new(width, height)?;
+    let context: OffscreenCanvasRenderingContext2d = canvas
+        .get_context("2d")?
+        .ok_or_else(|| JsValue::from_str("canvas has no 2d context"))?
+        .dyn_into()?;
+    context.draw_image_with_html_image_element_and_dw_and_dh(
+        image,
+        0.0,
+        0.0,
+        width.into(),
+        height.into(),
+    )?;
+
+    let webp = JsFuture::from(
+        canvas.convert_to_blob_with_options(ImageEncodeOptions::new().type_("image/webp"))?,
+    )
+    .await;
+    let blob = match webp {
+        Ok(blob) => blob,
+        Err(e) => {
+            error!("Could not re-encode resized button as WebP, falling back to PNG: {e:?}");
+            JsFuture::from(canvas.convert_to_blob()?).await?
+        }
+    };
+    blob.dyn_into()
+}
+
+// Reads `file`'s bytes, downscaling them first if the image exceeds
+// `MAX_EDGE`, and packages the (possibly resized) result, along with
+// the metadata the compound index keys off of, its content hash, and
+// its pixel dimensions, into a plain JS object that `indexed-db` can
+// structured-clone without tripping over the `File` serialization
+// bug. Also returns an object_url for display, which points at the
+// same (possibly resized) bytes that get stored.
+async fn prepare_button_record(file: &File) -> Result<(JsValue, String, String, (u32, u32)), JsValue> {
+    let buffer = JsFuture::from(file.array_buffer()).await?;
+    let original_bytes = Uint8Array::new(&buffer);
+    let original_type = file.type_();
+
+    let decode_blob = blob_from_bytes(&original_bytes, &original_type)?;
+    let decode_url = Url::create_object_url_with_blob(&decode_blob)?;
+    let image = load_image(&decode_url).await;
+    let _ = Url::revoke_object_url(&decode_url);
+    let image = image?;
+    let (width, height) = (image.natural_width(), image.natural_height());
+
+    let (bytes, content_type, width, height) = match scaled_dimensions(width, height) {
+        None => (original_bytes, original_type, width, height),
+        Some((scaled_width, scaled_height)) => {
+            let _slot = acquire_resize_slot().await;
+            let resized = downscale(&image, scaled_width, scaled_height).await?;
+            let resized_type = resized.type_();
+            let buffer = JsFuture::from(resized.array_buffer()).await?;
+            (
+                Uint8Array::new(&buffer),
+                resized_type,
+                scaled_width,
+                scaled_height,
+            )
+        }
+    };
+
+    let hash = sha256_hex(&bytes).await?;
+    let preview_blob = blob_from_bytes(&bytes, &content_type)?;
+    let preview_url = Url::create_object_url_with_blob(&preview_blob)?;
+
+    let record = Object::new();
+    Reflect::set(&record, &JsValue::from_str("name"), &file.name().into())?;
+    Reflect::set(
+        &record,
+        &JsValue::from_str("lastModified"),
+        &file.last_modified().into(),
+    )?;
+    Reflect::set(&record, &JsValue::from_str("size"), &bytes.length().into())?;
+    Reflect::set(&record, &JsValue::from_str("type"), &JsValue::from_str(&content_type))?;
+    Reflect::set(&record, &JsValue::from_str("bytes"), &bytes)?;
+    Reflect::set(&record, &JsValue::from_str("hash"), &JsValue::from_str(&hash))?;
+    Reflect::set(&record, &JsValue::from_str("width"), &width.into())?;
+    Reflect::set(&record, &JsValue::from_str("height"), &height.into())?;
+    Ok((record.into(), hash, preview_url, (width, height)))
+}
+
+// Whether a `changes` row records a button being added or removed.
+// Deletions are tombstones, not row removals, so that a sync peer
+// which only ever sees adds can still learn that a hash was deleted.
+#[derive(Clone, Copy, PartialEq)]
+enum ChangeOp {
+    Add,
+    Delete,
+}
+
+impl ChangeOp {
+    fn as_str(self) -> &'static str {
+        match self {
+            ChangeOp::Add => "add",
+            ChangeOp::Delete => "delete",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "add" => Some(ChangeOp::Add),
+            "delete" => Some(ChangeOp::Delete),
+            _ => None,
+        }
+    }
+}
+
+// Appends a `{ hash, op }` row to the `changes` store, within an
+// already-open transaction that includes it.
+async fn record_change(t: &Transaction<OurError>, hash: &str, op: ChangeOp) -> Result<(), Error<OurError>> {
+    let store = t.store(CHANGES)?;
+    let record = Object::new();
+    Reflect::set(&record, &JsValue::from_str("hash"), &JsValue::from_str(hash)).ok();
+    Reflect::set(&record, &JsValue::from_str("op"), &JsValue::from_str(op.as_str())).ok();
+    store.add(&record.into(), None).await?;
+    Ok(())
+}
+
+async fn store_record(t: Transaction<OurError>, record: JsValue, hash: String) {
     let store = match t.store(BUTTONS) {
         Ok(s) => s,
         Err(e) => {
@@ -107,26 +527,310 @@ async fn store_button(t: Transaction<OurError>, file: File) {
             return;
         }
     };
-    match store.add(&file, None).await {
-        Ok(_) => {
-            // Do not call done if store failed, because we'll get a panic.
-            if let Err(e) = t.done().await {
-                error!("Could not complete button storage transaction: {e:?}");
-            }
+
+    let hash_index = match store.index(HASH_INDEX) {
+        Ok(i) => i,
+        Err(e) => {
+            error!("Can't get hash index to check for duplicates: {e:?}");
+            return;
+        }
+    };
+    match hash_index.get(&JsValue::from_str(&hash)).await {
+        Ok(Some(_)) => {
+            info!("That button is already stored");
+            return;
         }
+        Ok(None) => {}
         Err(e) => {
-            if let Error::IdbError(idb::Error::DomException(d)) = e {
-                // I am not particularly happy about this code to detect a
-                // uniqueness constraint violation, but it appears to work
-                if d.name() == "ConstraintError" && d.message().contains("uniqueness") {
-                    info!("That button is already stored");
-                }
-            } else {
-                error!("Could not store button: {e:?}");
+            error!("Could not check for an existing button by hash: {e:?}");
+            return;
+        }
+    }
+
+    if let Err(e) = store.add(&record, None).await {
+        error!("Could not store button: {e:?}");
+        return;
+    }
+    if let Err(e) = record_change(&t, &hash, ChangeOp::Add).await {
+        error!("Could not record the change that stored {hash}: {e:?}");
+        return;
+    }
+    // Do not call done if either write failed, because we'll get a panic.
+    if let Err(e) = t.done().await {
+        error!("Could not complete button storage transaction: {e:?}");
+    }
+}
+
+// Prepares `file` for storage (decoding, downscaling, and hashing it)
+// then shows it as a button face and, if the database is open yet,
+// persists it.
+async fn process_and_store_button(db: Option<Database<OurError>>, file: File, link: Scope<App>) {
+    let (record, hash, url, dimensions) = match prepare_button_record(&file).await {
+        Ok(prepared) => prepared,
+        Err(e) => {
+            error!("Could not prepare {file:?} for storage: {e:?}");
+            return;
+        }
+    };
+
+    link.send_message(Msg::ButtonAdded(CustomFace {
+        url,
+        dimensions: Some(dimensions),
+    }));
+
+    if let Some(db) = db {
+        if let Ok(t) = db.transaction(&WRITE_STORE_NAMES, TransactionMode::ReadWrite) {
+            spawn_local(store_record(t, record, hash));
+        }
+    }
+}
+
+// Uploads local changes since the local counter we last uploaded to
+// `endpoint`, downloads the server's canonical change log since the
+// remote position we last applied, and reconciles by content hash:
+// we pull bytes for hashes we're missing, drop local rows whose hash
+// is tombstoned remotely, and on a hash with more than one pending
+// change, let the server's sequence number - not either side's local
+// bookkeeping - decide which one wins.
+async fn sync(db: Database<OurError>, endpoint: String) {
+    match sync_with(&db, &endpoint).await {
+        Ok(()) => info!("Sync with {endpoint} complete"),
+        Err(e) => error!("Sync with {endpoint} failed: {e:?}"),
+    }
+}
+
+// A pending row from our own `changes` store. `local_counter` is this
+// device's private IndexedDB auto-increment key: it only orders our
+// own uploads and has no meaning to any other device or the server.
+#[derive(Clone)]
+struct LocalChange {
+    local_counter: f64,
+    hash: String,
+    op: ChangeOp,
+}
+
+// A row from the server's canonical change log, keyed by a sequence
+// number the server assigns. Unlike `LocalChange::local_counter`,
+// `seq` is comparable across devices, so it's what conflict
+// resolution is decided by.
+#[derive(Clone)]
+struct RemoteChange {
+    seq: f64,
+    hash: String,
+    op: ChangeOp,
+}
+
+fn last_uploaded_key(endpoint: &str) -> String {
+    format!("mb-sync-uploaded:{endpoint}")
+}
+
+fn last_applied_key(endpoint: &str) -> String {
+    format!("mb-sync-applied:{endpoint}")
+}
+
+fn stored_counter(key: &str) -> f64 {
+    window()
+        .local_storage()
+        .ok()
+        .flatten()
+        .and_then(|storage| storage.get_item(key).ok().flatten())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0.0)
+}
+
+fn set_stored_counter(key: &str, counter: f64) {
+    if let Ok(Some(storage)) = window().local_storage() {
+        let _ = storage.set_item(key, &counter.to_string());
+    }
+}
+
+// All local changes with a local counter greater than `since`.
+async fn local_changes_since(db: &Database<OurError>, since: f64) -> Result<Vec<LocalChange>, Error<OurError>> {
+    let transaction = db.transaction(&[CHANGES], TransactionMode::ReadOnly)?;
+    let store = transaction.store(CHANGES)?;
+    let mut cursor = store.cursor().open().await?;
+    let mut changes = Vec::new();
+    while let Some(entry) = cursor.next().await? {
+        let local_counter = entry.key().as_f64().unwrap_or_default();
+        if local_counter > since {
+            if let Some(change) = local_change_from_value(local_counter, &entry.value()) {
+                changes.push(change);
             }
         }
     }
-    */
+    Ok(changes)
+}
+
+fn local_change_from_value(local_counter: f64, value: &JsValue) -> Option<LocalChange> {
+    let hash = Reflect::get(value, &JsValue::from_str("hash")).ok()?.as_string()?;
+    let op = Reflect::get(value, &JsValue::from_str("op")).ok()?.as_string()?;
+    Some(LocalChange { local_counter, hash, op: ChangeOp::parse(&op)? })
+}
+
+// The wire format for an upload is just `{ hash, op }`: the server
+// assigns its own sequence number, so our local counter doesn't
+// travel with it.
+fn local_change_to_json(change: &LocalChange) -> Result<JsValue, JsValue> {
+    let entry = Object::new();
+    Reflect::set(&entry, &JsValue::from_str("hash"), &JsValue::from_str(&change.hash))?;
+    Reflect::set(&entry, &JsValue::from_str("op"), &JsValue::from_str(change.op.as_str()))?;
+    Ok(entry.into())
+}
+
+fn remote_changes_from_json(json: &JsValue) -> Vec<RemoteChange> {
+    let array: js_sys::Array = match json.clone().dyn_into() {
+        Ok(array) => array,
+        Err(_) => return Vec::new(),
+    };
+    array
+        .iter()
+        .filter_map(|entry| {
+            let seq = Reflect::get(&entry, &JsValue::from_str("seq")).ok()?.as_f64()?;
+            let hash = Reflect::get(&entry, &JsValue::from_str("hash")).ok()?.as_string()?;
+            let op = Reflect::get(&entry, &JsValue::from_str("op")).ok()?.as_string()?;
+            Some(RemoteChange { seq, hash, op: ChangeOp::parse(&op)? })
+        })
+        .collect()
+}
+
+// Whether `hash` is already present in the buttons store.
+async fn has_local_hash(db: &Database<OurError>, hash: &str) -> Result<bool, Error<OurError>> {
+    let transaction = db.transaction(&[BUTTONS], TransactionMode::ReadOnly)?;
+    let store = transaction.store(BUTTONS)?;
+    let index = store.index(HASH_INDEX)?;
+    Ok(index.get(&JsValue::from_str(hash)).await?.is_some())
+}
+
+// Removes the local button (if any) whose content hash is `hash`.
+// This does not append a new `changes` row: the deletion is already a
+// fact of record on the peer we synced it from.
+async fn delete_local_by_hash(db: &Database<OurError>, hash: &str) -> Result<(), Error<OurError>> {
+    let transaction = db.transaction(&[BUTTONS], TransactionMode::ReadWrite)?;
+    let store = transaction.store(BUTTONS)?;
+    let index = store.index(HASH_INDEX)?;
+    if let Some(key) = index.get_key(&JsValue::from_str(hash)).await? {
+        store.delete(&key).await?;
+    }
+    transaction.done().await
+}
+
+// Fetches the bytes for `hash` from the server and adds them to the
+// local buttons store through the same ArrayBuffer record shape every
+// other button uses. Does not append a `changes` row, for the same
+// reason `delete_local_by_hash` doesn't.
+async fn add_remote_blob(db: &Database<OurError>, endpoint: &str, hash: &str) -> Result<(), JsValue> {
+    let response = fetch(&format!("{endpoint}/blobs/{hash}"), "GET", None).await?;
+    let content_type = response.headers().get("Content-Type")?.unwrap_or_default();
+    let buffer = JsFuture::from(response.array_buffer()?).await?;
+    let bytes = Uint8Array::new(&buffer);
+
+    let blob = blob_from_bytes(&bytes, &content_type)?;
+    let url = Url::create_object_url_with_blob(&blob)?;
+    let dimensions = decode_dimensions(&url).await;
+    let _ = Url::revoke_object_url(&url);
+    let (width, height) = dimensions.unwrap_or((0, 0));
+
+    let record = Object::new();
+    Reflect::set(&record, &JsValue::from_str("name"), &JsValue::from_str(hash))?;
+    Reflect::set(&record, &JsValue::from_str("lastModified"), &JsValue::from_f64(0.0))?;
+    Reflect::set(&record, &JsValue::from_str("size"), &bytes.length().into())?;
+    Reflect::set(&record, &JsValue::from_str("type"), &JsValue::from_str(&content_type))?;
+    Reflect::set(&record, &JsValue::from_str("bytes"), &bytes)?;
+    Reflect::set(&record, &JsValue::from_str("hash"), &JsValue::from_str(hash))?;
+    Reflect::set(&record, &JsValue::from_str("width"), &width.into())?;
+    Reflect::set(&record, &JsValue::from_str("height"), &height.into())?;
+
+    let transaction = db
+        .transaction(&[BUTTONS], TransactionMode::ReadWrite)
+        .map_err(|e| JsValue::from_str(&format!("{e:?}")))?;
+    let store = transaction
+        .store(BUTTONS)
+        .map_err(|e| JsValue::from_str(&format!("{e:?}")))?;
+    store
+        .add(&record.into(), None)
+        .await
+        .map_err(|e| JsValue::from_str(&format!("{e:?}")))?;
+    transaction
+        .done()
+        .await
+        .map_err(|e| JsValue::from_str(&format!("{e:?}")))
+}
+
+async fn fetch(url: &str, method: &str, body: Option<&str>) -> Result<Response, JsValue> {
+    let init = RequestInit::new();
+    init.set_method(method);
+    if let Some(body) = body {
+        init.set_body(&JsValue::from_str(body));
+    }
+    let request = Request::new_with_str_and_init(url, &init)?;
+    request.headers().set("Content-Type", "application/json")?;
+    let response = JsFuture::from(window().fetch_with_request(&request)).await?;
+    response.dyn_into()
+}
+
+async fn sync_with(db: &Database<OurError>, endpoint: &str) -> Result<(), JsValue> {
+    let last_uploaded = stored_counter(&last_uploaded_key(endpoint));
+    let last_applied = stored_counter(&last_applied_key(endpoint));
+
+    let local_changes = local_changes_since(db, last_uploaded)
+        .await
+        .map_err(|e| JsValue::from_str(&format!("{e:?}")))?;
+
+    let upload = js_sys::Array::new();
+    for change in &local_changes {
+        upload.push(&local_change_to_json(change)?);
+    }
+    let request = Object::new();
+    Reflect::set(&request, &JsValue::from_str("since"), &last_applied.into())?;
+    Reflect::set(&request, &JsValue::from_str("changes"), &upload)?;
+    let body = js_sys::JSON::stringify(&request)?
+        .as_string()
+        .unwrap_or_default();
+
+    let response = fetch(&format!("{endpoint}/changes"), "POST", Some(&body)).await?;
+    let remote_json = JsFuture::from(response.json()?).await?;
+    let remote_changes = remote_changes_from_json(&remote_json);
+
+    // The response is the server's canonical change log since
+    // `last_applied`, already merged across every device - including
+    // our own upload, now stamped with a server sequence number. Both
+    // sides have at most one pending op per hash, but dedupe
+    // defensively: on a conflict, `seq` is the only clock comparable
+    // across devices, so the higher one wins.
+    let mut by_hash: Vec<RemoteChange> = Vec::new();
+    for change in remote_changes {
+        match by_hash.iter_mut().find(|existing| existing.hash == change.hash) {
+            Some(existing) if change.seq > existing.seq => *existing = change,
+            Some(_) => {}
+            None => by_hash.push(change),
+        }
+    }
+
+    let mut highest_applied = last_applied;
+    for change in &by_hash {
+        highest_applied = highest_applied.max(change.seq);
+        let outcome = match change.op {
+            ChangeOp::Delete => delete_local_by_hash(db, &change.hash)
+                .await
+                .map_err(|e| JsValue::from_str(&format!("{e:?}"))),
+            ChangeOp::Add => match has_local_hash(db, &change.hash).await {
+                Ok(true) => Ok(()),
+                Ok(false) => add_remote_blob(db, endpoint, &change.hash).await,
+                Err(e) => Err(JsValue::from_str(&format!("{e:?}"))),
+            },
+        };
+        if let Err(e) = outcome {
+            error!("Could not reconcile change for {}: {e:?}", change.hash);
+        }
+    }
+
+    // The changes we uploaded are acknowledged once the server has
+    // folded them into the log above, even if that log turned out
+    // empty (e.g. we're the only device and it already had them).
+    let uploaded_through = local_changes.iter().map(|c| c.local_counter).fold(last_uploaded, f64::max);
+    set_stored_counter(&last_uploaded_key(endpoint), uploaded_through);
+    set_stored_counter(&last_applied_key(endpoint), highest_applied);
+    Ok(())
 }
 
 #[derive(Default)]
@@ -153,18 +857,32 @@ impl From<MouseEvent> for ClickAction {
 
 enum Msg {
     DbBuilt(Database<OurError>),
-    ButtonsRead(Vec<String>),
+    ButtonsRead(Vec<CustomFace>),
+    ButtonAdded(CustomFace),
     Clicked(ClickAction),
     StoreButton(File),
+    Sync,
 }
 
 impl From<MouseEvent> for Msg {
     fn from(event: MouseEvent) -> Self {
-        Msg::Clicked(event.into())
+        // Ctrl-click is a hidden gesture to kick off a sync, the same
+        // way shift-click is a hidden gesture to upload an image.
+        if event.ctrl_key() {
+            Msg::Sync
+        } else {
+            Msg::Clicked(event.into())
+        }
     }
 }
 
 static STORE_NAMES: [&str; 1] = [BUTTONS];
+static WRITE_STORE_NAMES: [&str; 2] = [BUTTONS, CHANGES];
+
+// Where `Msg::Sync` ships local changes to and pulls the server's
+// change set from. A real deployment would make this configurable;
+// this app doesn't have settings UI yet, so it's a constant.
+const SYNC_ENDPOINT: &str = "/api/buttons";
 
 impl App {
     fn upload_image(&mut self, link: Scope<Self>) {
@@ -218,10 +936,17 @@ impl App {
         ));
         input.click();
     }
+}
 
-    fn add_custom_button(&mut self, url: String) {
-        self.button.add_custom(url);
-    }
+// A custom button face: the object_url to draw it from, plus its
+// intrinsic pixel dimensions if we know them (so we can reserve the
+// right amount of space before the bitmap paints). `dimensions` is
+// `None` for a face read back from a stored record that has no
+// `width`/`height` on it (e.g. one whose backfill failed).
+#[derive(Clone, PartialEq)]
+struct CustomFace {
+    url: String,
+    dimensions: Option<(u32, u32)>,
 }
 
 #[derive(Default)]
@@ -233,7 +958,7 @@ enum ButtonFace {
 }
 
 impl ButtonFace {
-    fn incr(&mut self, faces: &[String]) {
+    fn incr(&mut self, faces: &[CustomFace]) {
         use ButtonFace::*;
 
         *self = match self {
@@ -249,7 +974,7 @@ impl ButtonFace {
 #[derive(Default)]
 struct Button {
     button_face: ButtonFace,
-    custom_faces: Vec<String>,
+    custom_faces: Vec<CustomFace>,
 }
 
 impl Button {
@@ -257,17 +982,17 @@ impl Button {
         self.button_face.incr(&self.custom_faces)
     }
 
-    fn add_custom(&mut self, url: String) {
-        match self.custom_faces.iter().position(|face| face == &url) {
+    fn add_custom(&mut self, face: CustomFace) {
+        match self.custom_faces.iter().position(|f| f.url == face.url) {
             Some(i) => self.button_face = ButtonFace::Custom(i),
             None => {
                 self.button_face = ButtonFace::Custom(self.custom_faces.len());
-                self.custom_faces.push(url);
+                self.custom_faces.push(face);
             }
         }
     }
 
-    fn add(&mut self, mut buttons: Vec<String>) -> bool {
+    fn add(&mut self, mut buttons: Vec<CustomFace>) -> bool {
         if buttons.is_empty() {
             false
         } else {
@@ -283,13 +1008,16 @@ impl Button {
         match &self.button_face {
             Top => ("button-wrapper examine", None),
             Bottom => ("button-wrapper examine flipped", None),
-            Custom(i) => (
-                "button-wrapper examine",
-                Some(format!(
-                    "background-image: url(\"{}\")",
-                    self.custom_faces[*i]
-                )),
-            ),
+            Custom(i) => {
+                let face = &self.custom_faces[*i];
+                let mut style = format!("background-image: url(\"{}\")", face.url);
+                if let Some((width, height)) = face.dimensions {
+                    style.push_str(&format!(
+                        "; aspect-ratio: {width} / {height}; background-size: contain"
+                    ));
+                }
+                ("button-wrapper examine", Some(style))
+            }
         }
     }
 
@@ -332,16 +1060,15 @@ impl Component for App {
                 true
             }
             StoreButton(file) => {
-                if let Ok(url) = Url::create_object_url_with_blob(&file) {
-                    self.add_custom_button(url);
-                }
-                if let Some(db) = &self.db {
-                    /*
-                    if let Ok(t) = db.transaction(&STORE_NAMES, TransactionMode::ReadWrite) {
-                        spawn_local(store_button(t, file));
-                    }
-                    */
-                }
+                spawn_local(process_and_store_button(
+                    self.db.clone(),
+                    file,
+                    ctx.link().clone(),
+                ));
+                false
+            }
+            ButtonAdded(face) => {
+                self.button.add_custom(face);
                 true
             }
             DbBuilt(db) => {
@@ -350,6 +1077,14 @@ impl Component for App {
                 false
             }
             ButtonsRead(buttons) => self.button.add(buttons),
+            Sync => {
+                if let Some(db) = self.db.clone() {
+                    spawn_local(sync(db, SYNC_ENDPOINT.to_string()));
+                } else {
+                    info!("Can't sync yet, the database isn't open");
+                }
+                false
+            }
         }
     }
 }